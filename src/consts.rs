@@ -0,0 +1,9 @@
+/// Shortest a player name may be (after trimming whitespace).
+pub const MIN_PLAYER_NAME_LENGTH: usize = 1;
+/// Longest a player name may be (after trimming whitespace).
+pub const MAX_PLAYER_NAME_LENGTH: usize = 24;
+
+/// Default cap on players in a single game.
+pub const DEFAULT_MAX_PLAYERS: usize = 8;
+/// Default score awarded per correctly submitted solution.
+pub const DEFAULT_POINTS_PER_SOLVE: i32 = 1;
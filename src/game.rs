@@ -1,9 +1,29 @@
+use actix::{Addr, Recipient};
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use thiserror::Error;
+use tracing::info;
 use uuid::Uuid;
 
+use crate::consts::{MAX_PLAYER_NAME_LENGTH, MIN_PLAYER_NAME_LENGTH};
+use crate::session::{MatchFound, OutgoingMessage, ServerMessage, WsGameSession};
+use crate::token;
+
+/// How long a disconnected player's seat is held before they're removed.
+const RECONNECT_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// How often the garbage collector scans for abandoned games.
+const GC_INTERVAL: Duration = Duration::from_secs(60);
+/// Absolute cap on how long any game, regardless of activity, may live.
+const MAX_GAME_LIFETIME: Duration = Duration::from_secs(4 * 60 * 60);
+/// A lobby-phase game with no activity for this long is considered abandoned.
+const MAX_LOBBY_IDLE: Duration = Duration::from_secs(10 * 60);
+/// An active game with no activity for this long is considered abandoned.
+const MAX_ACTIVE_IDLE: Duration = Duration::from_secs(30 * 60);
+
 #[derive(Debug, Error)]
 pub enum GameError {
     #[error("Game not found with code: {0}")]
@@ -12,99 +32,405 @@ pub enum GameError {
     GameNotActive,
     #[error("Player not found")]
     PlayerNotFound,
+    #[error("Resume token is invalid or expired")]
+    InvalidResumeToken,
+    #[error("Game has not finished yet")]
+    GameNotFinished,
+    #[error("Game is full (max {0} players)")]
+    GameFull(usize),
+    #[error("Player name must be between {MIN_PLAYER_NAME_LENGTH} and {MAX_PLAYER_NAME_LENGTH} characters")]
+    InvalidPlayerName,
+    #[error("Hints are not allowed in this game")]
+    HintsNotAllowed,
+    #[error("Invalid game rules: {0}")]
+    InvalidRules(String),
+}
+
+/// Validates `name` and returns the trimmed form that should actually be
+/// stored. Length is measured in characters, not bytes, so multi-byte
+/// names aren't penalized relative to ASCII ones.
+fn validate_player_name(name: &str) -> Result<String, GameError> {
+    let trimmed = name.trim();
+    let len = trimmed.chars().count();
+    if !(MIN_PLAYER_NAME_LENGTH..=MAX_PLAYER_NAME_LENGTH).contains(&len) {
+        return Err(GameError::InvalidPlayerName);
+    }
+    Ok(trimmed.to_string())
+}
+
+/// Per-game rules negotiated at creation time, mirroring how many players
+/// may join, how scoring works, and whether hints are allowed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GameRules {
+    #[serde(default = "default_max_players")]
+    pub max_players: usize,
+    #[serde(default = "default_points_per_solve")]
+    pub points_per_solve: i32,
+    #[serde(default)]
+    pub round_count: Option<u32>,
+    #[serde(default = "default_allow_hints")]
+    pub allow_hints: bool,
+}
+
+fn default_max_players() -> usize {
+    crate::consts::DEFAULT_MAX_PLAYERS
+}
+
+fn default_points_per_solve() -> i32 {
+    crate::consts::DEFAULT_POINTS_PER_SOLVE
+}
+
+fn default_allow_hints() -> bool {
+    true
+}
+
+impl Default for GameRules {
+    fn default() -> Self {
+        Self {
+            max_players: default_max_players(),
+            points_per_solve: default_points_per_solve(),
+            round_count: None,
+            allow_hints: default_allow_hints(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GamePhase {
+    Lobby,
+    Active,
+    Finished,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Player {
     pub name: String,
     pub score: i32,
+    pub connected: bool,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GameState {
     pub players: HashMap<Uuid, Player>,
-    pub is_active: bool,
+    pub phase: GamePhase,
+    pub rules: GameRules,
+    /// Count of solves submitted without a hint so far, checked against
+    /// `rules.round_count` to auto-finish the game.
+    pub rounds_completed: u32,
     created_at: chrono::DateTime<chrono::Utc>,
+    #[serde(skip, default = "Instant::now")]
+    last_activity: Instant,
+    #[serde(skip)]
+    rematch_votes: HashSet<Uuid>,
 }
 
 impl GameState {
-    pub fn new() -> Self {
+    pub fn new(rules: GameRules) -> Self {
         Self {
             players: HashMap::new(),
-            is_active: false,
+            phase: GamePhase::Lobby,
+            rules,
+            rounds_completed: 0,
             created_at: chrono::Utc::now(),
+            last_activity: Instant::now(),
+            rematch_votes: HashSet::new(),
         }
     }
 
+    fn touch(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
     pub fn add_player(&mut self, id: Uuid, name: String) -> Player {
-        let player = Player { name, score: 0 };
+        let player = Player { name, score: 0, connected: true };
         self.players.insert(id, player.clone());
+        self.touch();
         player
     }
 
     pub fn remove_player(&mut self, id: &Uuid) -> Option<Player> {
+        self.rematch_votes.remove(id);
         self.players.remove(id)
     }
 
-    pub fn increment_score(&mut self, id: &Uuid) -> Result<i32, GameError> {
+    pub fn increment_score(&mut self, id: &Uuid, points: i32) -> Result<i32, GameError> {
         let player = self.players.get_mut(id).ok_or(GameError::PlayerNotFound)?;
-        player.score += 1;
+        player.score += points;
         Ok(player.score)
     }
 
     pub fn get_players(&self) -> Vec<Player> {
         self.players.values().cloned().collect()
     }
+
+    fn connected_player_ids(&self) -> HashSet<Uuid> {
+        self.players
+            .iter()
+            .filter(|(_, player)| player.connected)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Resets scores and rematch votes and moves the game back into play.
+    fn start_rematch(&mut self) {
+        for player in self.players.values_mut() {
+            player.score = 0;
+        }
+        self.rematch_votes.clear();
+        self.rounds_completed = 0;
+        self.phase = GamePhase::Active;
+    }
+}
+
+/// A session parked in the random-matchmaking queue, waiting to be paired.
+struct PendingPlayer {
+    player_id: Uuid,
+    player_name: String,
+    addr: Addr<WsGameSession>,
+}
+
+/// Outcome of a `PlayRandom` request.
+pub enum RandomMatchOutcome {
+    /// No opponent was waiting; the caller has been parked in the queue.
+    Waiting,
+    /// An opponent was already waiting and a game was created for both.
+    Matched { code: String, game_state: GameState },
 }
 
 pub struct GameManager {
     games: RwLock<HashMap<String, GameState>>,
+    registry: RwLock<HashMap<String, HashMap<Uuid, Recipient<OutgoingMessage>>>>,
+    random_queue: RwLock<VecDeque<PendingPlayer>>,
 }
 
 impl GameManager {
     pub fn new() -> Self {
         Self {
             games: RwLock::new(HashMap::new()),
+            registry: RwLock::new(HashMap::new()),
+            random_queue: RwLock::new(VecDeque::new()),
         }
     }
 
-    pub fn create_game(&self, code: String, player_id: Uuid, player_name: String) -> GameState {
+    /// Parks `player_id` in the random-matchmaking queue, or, if another
+    /// player is already waiting, pairs them into a freshly started game.
+    pub fn play_random(
+        &self,
+        player_id: Uuid,
+        player_name: String,
+        addr: Addr<WsGameSession>,
+    ) -> Result<RandomMatchOutcome, GameError> {
+        let player_name = validate_player_name(&player_name)?;
+
+        let opponent = loop {
+            match self.random_queue.write().pop_front() {
+                None => break None,
+                Some(p) if p.addr.connected() => break Some(p),
+                Some(stale) => {
+                    info!("Dropping stale random-queue entry for player {}", stale.player_id);
+                    continue;
+                }
+            }
+        };
+
+        let Some(opponent) = opponent else {
+            self.random_queue.write().push_back(PendingPlayer {
+                player_id,
+                player_name,
+                addr,
+            });
+            return Ok(RandomMatchOutcome::Waiting);
+        };
+
+        let code = format!("{:06X}", rand::random::<u32>());
+        let mut game_state = GameState::new(GameRules::default());
+        game_state.add_player(opponent.player_id, opponent.player_name);
+        game_state.add_player(player_id, player_name);
+        game_state.phase = GamePhase::Active;
+
+        self.games.write().insert(code.clone(), game_state.clone());
+
+        self.register(&code, opponent.player_id, opponent.addr.clone().recipient());
+        self.register(&code, player_id, addr.recipient());
+
+        opponent.addr.do_send(MatchFound { code: code.clone() });
+
+        Ok(RandomMatchOutcome::Matched { code, game_state })
+    }
+
+    /// Removes `player_id` from the random-matchmaking queue, if present.
+    /// Called when a session disconnects while still waiting for an
+    /// opponent, so it doesn't linger as a ghost entry.
+    pub fn cancel_play_random(&self, player_id: &Uuid) {
+        self.random_queue.write().retain(|p| p.player_id != *player_id);
+    }
+
+    pub fn create_game(
+        &self,
+        code: String,
+        player_id: Uuid,
+        player_name: String,
+        rules: GameRules,
+        recipient: Recipient<OutgoingMessage>,
+    ) -> Result<(GameState, String), GameError> {
+        let player_name = validate_player_name(&player_name)?;
+        if rules.max_players < 1 {
+            return Err(GameError::InvalidRules("max_players must be at least 1".to_string()));
+        }
+
         let mut games = self.games.write();
-        let mut game_state = GameState::new();
+        let mut game_state = GameState::new(rules);
         game_state.add_player(player_id, player_name);
         games.insert(code.clone(), game_state.clone());
-        game_state
+        drop(games);
+
+        self.register(&code, player_id, recipient);
+
+        let resume_token = token::issue(&code, player_id);
+        Ok((game_state, resume_token))
     }
 
-    pub fn join_game(&self, code: &str, player_id: Uuid, player_name: String) -> Result<GameState, GameError> {
+    pub fn join_game(
+        &self,
+        code: &str,
+        player_id: Uuid,
+        player_name: String,
+        recipient: Recipient<OutgoingMessage>,
+    ) -> Result<(GameState, String), GameError> {
+        let player_name = validate_player_name(&player_name)?;
+
         let mut games = self.games.write();
         let game = games.get_mut(code).ok_or(GameError::GameNotFound(code.to_string()))?;
+
+        if game.players.len() >= game.rules.max_players {
+            return Err(GameError::GameFull(game.rules.max_players));
+        }
+
         game.add_player(player_id, player_name);
-        Ok(game.clone())
+        let game_state = game.clone();
+        drop(games);
+
+        self.register(code, player_id, recipient);
+
+        let resume_token = token::issue(code, player_id);
+        Ok((game_state, resume_token))
     }
 
     pub fn start_game(&self, code: &str) -> Result<(), GameError> {
         let mut games = self.games.write();
         let game = games.get_mut(code).ok_or(GameError::GameNotFound(code.to_string()))?;
-        game.is_active = true;
+        game.phase = GamePhase::Active;
         Ok(())
     }
 
-    pub fn submit_solution(&self, code: &str, player_id: &Uuid, used_hint: bool) -> Result<Vec<Player>, GameError> {
+    /// Records a solve. Returns the updated player list and whether this
+    /// solve brought the game up to its configured `round_count` and finished
+    /// it, in which case the caller should announce `GameEnded` too.
+    pub fn submit_solution(
+        &self,
+        code: &str,
+        player_id: &Uuid,
+        used_hint: bool,
+    ) -> Result<(Vec<Player>, bool), GameError> {
         let mut games = self.games.write();
         let game = games.get_mut(code).ok_or(GameError::GameNotFound(code.to_string()))?;
 
-        if !game.is_active {
+        if game.phase != GamePhase::Active {
             return Err(GameError::GameNotActive);
         }
 
+        if used_hint && !game.rules.allow_hints {
+            return Err(GameError::HintsNotAllowed);
+        }
+
+        let mut just_finished = false;
         if !used_hint {
-            game.increment_score(player_id)?;
+            game.increment_score(player_id, game.rules.points_per_solve)?;
+            game.rounds_completed += 1;
+
+            if let Some(round_count) = game.rules.round_count {
+                if game.rounds_completed >= round_count {
+                    game.phase = GamePhase::Finished;
+                    game.rematch_votes.clear();
+                    just_finished = true;
+                }
+            }
         }
+        game.touch();
+
+        Ok((game.get_players(), just_finished))
+    }
+
+    /// Ends an active game, moving it into `Finished`.
+    pub fn end_game(&self, code: &str) -> Result<Vec<Player>, GameError> {
+        let mut games = self.games.write();
+        let game = games.get_mut(code).ok_or(GameError::GameNotFound(code.to_string()))?;
+
+        if game.phase != GamePhase::Active {
+            return Err(GameError::GameNotActive);
+        }
+
+        game.phase = GamePhase::Finished;
+        game.rematch_votes.clear();
 
         Ok(game.get_players())
     }
 
+    /// Records that `player_id` wants a rematch. Returns that player's info
+    /// so the caller can announce the request to the rest of the game.
+    pub fn request_rematch(&self, code: &str, player_id: &Uuid) -> Result<Player, GameError> {
+        let mut games = self.games.write();
+        let game = games.get_mut(code).ok_or(GameError::GameNotFound(code.to_string()))?;
+
+        if game.phase != GamePhase::Finished {
+            return Err(GameError::GameNotFinished);
+        }
+        let player = game.players.get(player_id).cloned().ok_or(GameError::PlayerNotFound)?;
+
+        game.rematch_votes.insert(*player_id);
+
+        Ok(player)
+    }
+
+    /// Records an acceptance. If every connected player has now accepted,
+    /// the game is reset and restarted and the fresh `GameState` is
+    /// returned; otherwise returns `None` to indicate the vote is pending.
+    pub fn accept_rematch(&self, code: &str, player_id: &Uuid) -> Result<Option<GameState>, GameError> {
+        let mut games = self.games.write();
+        let game = games.get_mut(code).ok_or(GameError::GameNotFound(code.to_string()))?;
+
+        if game.phase != GamePhase::Finished {
+            return Err(GameError::GameNotFinished);
+        }
+        if !game.players.contains_key(player_id) {
+            return Err(GameError::PlayerNotFound);
+        }
+
+        game.rematch_votes.insert(*player_id);
+
+        if game.rematch_votes.is_superset(&game.connected_player_ids()) {
+            game.start_rematch();
+            Ok(Some(game.clone()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Clears any in-progress rematch vote for the game.
+    pub fn reject_rematch(&self, code: &str) -> Result<(), GameError> {
+        let mut games = self.games.write();
+        let game = games.get_mut(code).ok_or(GameError::GameNotFound(code.to_string()))?;
+
+        if game.phase != GamePhase::Finished {
+            return Err(GameError::GameNotFinished);
+        }
+
+        game.rematch_votes.clear();
+        Ok(())
+    }
+
     pub fn remove_player(&self, code: &str, player_id: &Uuid) -> Option<()> {
         let mut games = self.games.write();
         let game = games.get_mut(code)?;
@@ -115,6 +441,145 @@ impl GameManager {
             games.remove(code);
         }
 
+        drop(games);
+        self.deregister(code, player_id);
+
         Some(())
     }
+
+    /// Marks a player as disconnected instead of removing them outright, and
+    /// schedules a sweep that removes them if they haven't resumed within
+    /// `RECONNECT_GRACE_PERIOD`.
+    pub fn disconnect_player(self: &Arc<Self>, code: &str, player_id: &Uuid) {
+        {
+            let mut games = self.games.write();
+            if let Some(player) = games.get_mut(code).and_then(|g| g.players.get_mut(player_id)) {
+                player.connected = false;
+            }
+        }
+        self.deregister(code, player_id);
+
+        let manager = Arc::clone(self);
+        let code = code.to_string();
+        let player_id = *player_id;
+        tokio::spawn(async move {
+            tokio::time::sleep(RECONNECT_GRACE_PERIOD).await;
+            manager.reap_if_still_disconnected(&code, &player_id);
+        });
+    }
+
+    /// Resumes a session using a previously issued token, rebinding it to
+    /// the existing player entry rather than creating a new one.
+    pub fn resume(
+        &self,
+        resume_token: &str,
+        recipient: Recipient<OutgoingMessage>,
+    ) -> Result<(String, Uuid, GameState), GameError> {
+        let (code, player_id) = token::validate(resume_token).ok_or(GameError::InvalidResumeToken)?;
+
+        let mut games = self.games.write();
+        let game = games.get_mut(&code).ok_or_else(|| GameError::GameNotFound(code.clone()))?;
+        let player = game.players.get_mut(&player_id).ok_or(GameError::PlayerNotFound)?;
+        player.connected = true;
+        let game_state = game.clone();
+        drop(games);
+
+        self.register(&code, player_id, recipient);
+
+        Ok((code, player_id, game_state))
+    }
+
+    /// Spawns a background task that periodically reaps abandoned and
+    /// stale games: anything past `MAX_GAME_LIFETIME`, any lobby that's
+    /// seen no activity for `MAX_LOBBY_IDLE`, or any active game idle for
+    /// `MAX_ACTIVE_IDLE`.
+    pub fn spawn_garbage_collector(self: &Arc<Self>) {
+        let manager = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(GC_INTERVAL);
+            loop {
+                interval.tick().await;
+                manager.collect_garbage();
+            }
+        });
+    }
+
+    fn collect_garbage(&self) {
+        let now = chrono::Utc::now();
+
+        let mut games = self.games.write();
+        let stale_codes: Vec<String> = games
+            .iter()
+            .filter(|(_, game)| {
+                let too_old = now
+                    .signed_duration_since(game.created_at)
+                    .to_std()
+                    .map(|age| age > MAX_GAME_LIFETIME)
+                    .unwrap_or(false);
+                let idle_limit = match game.phase {
+                    GamePhase::Lobby | GamePhase::Finished => MAX_LOBBY_IDLE,
+                    GamePhase::Active => MAX_ACTIVE_IDLE,
+                };
+                too_old || game.last_activity.elapsed() > idle_limit
+            })
+            .map(|(code, _)| code.clone())
+            .collect();
+
+        for code in &stale_codes {
+            games.remove(code);
+        }
+        drop(games);
+
+        for code in stale_codes {
+            info!("Garbage-collected abandoned game {}", code);
+            self.registry.write().remove(&code);
+        }
+    }
+
+    fn reap_if_still_disconnected(&self, code: &str, player_id: &Uuid) {
+        let mut games = self.games.write();
+        let Some(game) = games.get_mut(code) else { return };
+
+        let still_disconnected = game.players.get(player_id).is_some_and(|p| !p.connected);
+        if !still_disconnected {
+            return;
+        }
+
+        game.remove_player(player_id);
+        info!("Reaped disconnected player {} from game {}", player_id, code);
+
+        if game.players.is_empty() {
+            games.remove(code);
+        }
+    }
+
+    /// Registers a session's recipient so it receives broadcasts for `code`.
+    pub fn register(&self, code: &str, player_id: Uuid, recipient: Recipient<OutgoingMessage>) {
+        self.registry
+            .write()
+            .entry(code.to_string())
+            .or_default()
+            .insert(player_id, recipient);
+    }
+
+    /// Removes a session's recipient from the broadcast registry for `code`.
+    pub fn deregister(&self, code: &str, player_id: &Uuid) {
+        let mut registry = self.registry.write();
+        if let Some(recipients) = registry.get_mut(code) {
+            recipients.remove(player_id);
+            if recipients.is_empty() {
+                registry.remove(code);
+            }
+        }
+    }
+
+    /// Sends `message` to every session currently registered for `code`.
+    pub fn broadcast(&self, code: &str, message: ServerMessage) {
+        let registry = self.registry.read();
+        if let Some(recipients) = registry.get(code) {
+            for recipient in recipients.values() {
+                recipient.do_send(OutgoingMessage(message.clone()));
+            }
+        }
+    }
 }
\ No newline at end of file
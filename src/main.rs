@@ -3,8 +3,10 @@ use actix_web::{middleware, web, App, HttpResponse, HttpServer};
 use std::sync::Arc;
 use tracing::info;
 
+mod consts;
 mod game;
 mod session;
+mod token;
 
 async fn health_check() -> HttpResponse {
     HttpResponse::Ok().json(serde_json::json!({
@@ -24,6 +26,7 @@ async fn main() -> std::io::Result<()> {
 
     // Shared game state
     let game_manager = Arc::new(game::GameManager::new());
+    game_manager.spawn_garbage_collector();
     let game_manager_data = web::Data::new(game_manager);
 
     // Start server
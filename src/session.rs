@@ -1,4 +1,4 @@
-use actix::{Actor, ActorContext, StreamHandler, AsyncContext};
+use actix::{Actor, ActorContext, AsyncContext, Handler, Message, StreamHandler};
 use actix_web::{web, Error, HttpRequest, HttpResponse};
 use actix_web_actors::ws;
 use serde::{Deserialize, Serialize};
@@ -14,22 +14,53 @@ const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 #[derive(Debug, Deserialize)]
 #[serde(tag = "event", rename_all = "camelCase")]
 enum ClientMessage {
-    CreateGame { player_name: String },
+    CreateGame {
+        player_name: String,
+        #[serde(default)]
+        rules: crate::game::GameRules,
+    },
     JoinGame { code: String, player_name: String },
     StartGame,
     SubmitSolution { used_hint: bool },
+    Resume { token: String },
+    EndGame,
+    RequestRematch,
+    AcceptRematch,
+    RejectRematch,
+    PlayRandom { player_name: String },
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Clone, Debug, Serialize)]
 #[serde(tag = "event", rename_all = "camelCase")]
-enum ServerMessage {
-    GameCreated { game_code: String },
+pub enum ServerMessage {
+    GameCreated { game_code: String, resume_token: String },
     PlayerJoined { players: Vec<crate::game::Player> },
+    ResumeToken { resume_token: String },
     GameStarted,
     UpdateScores { players: Vec<crate::game::Player> },
+    GameEnded { players: Vec<crate::game::Player> },
+    RematchRequested { player: crate::game::Player },
+    RematchDeclined,
+    WaitingForOpponent,
+    OpponentConnected { game_code: String },
     Error { message: String },
 }
 
+/// A message fanned out by `GameManager::broadcast` to every session
+/// registered for a game code.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct OutgoingMessage(pub ServerMessage);
+
+/// Sent directly to a session parked in the random-matchmaking queue once
+/// `GameManager` has paired it with an opponent, so it can adopt the new
+/// game code without waiting on the next client message.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct MatchFound {
+    pub code: String,
+}
+
 pub struct WsGameSession {
     id: Uuid,
     game_code: Option<String>,
@@ -60,27 +91,39 @@ impl WsGameSession {
 
     fn handle_message(&mut self, msg: ClientMessage, ctx: &mut ws::WebsocketContext<Self>) {
         let response = match msg {
-            ClientMessage::CreateGame { player_name } => {
+            ClientMessage::CreateGame { player_name, rules } => {
                 let game_code = format!("{:06X}", rand::random::<u32>());
                 info!("Creating game {} for player {}", game_code, player_name);
 
-                let _game_state = self.game_manager.create_game(
+                match self.game_manager.create_game(
                     game_code.clone(),
                     self.id,
-                    player_name
-                );
-                self.game_code = Some(game_code.clone());
-
-                ServerMessage::GameCreated { game_code }
+                    player_name,
+                    rules,
+                    ctx.address().recipient(),
+                ) {
+                    Ok((_game_state, resume_token)) => {
+                        self.game_code = Some(game_code.clone());
+                        ServerMessage::GameCreated { game_code, resume_token }
+                    }
+                    Err(e) => ServerMessage::Error { message: e.to_string() }
+                }
             }
 
             ClientMessage::JoinGame { code, player_name } => {
                 info!("Player {} attempting to join game {}", player_name, code);
 
-                match self.game_manager.join_game(&code, self.id, player_name) {
-                    Ok(game_state) => {
-                        self.game_code = Some(code);
-                        ServerMessage::PlayerJoined { players: game_state.get_players() }
+                match self
+                    .game_manager
+                    .join_game(&code, self.id, player_name, ctx.address().recipient())
+                {
+                    Ok((game_state, resume_token)) => {
+                        self.game_code = Some(code.clone());
+                        self.game_manager.broadcast(
+                            &code,
+                            ServerMessage::PlayerJoined { players: game_state.get_players() },
+                        );
+                        ServerMessage::ResumeToken { resume_token }
                     }
                     Err(e) => ServerMessage::Error { message: e.to_string() }
                 }
@@ -89,7 +132,10 @@ impl WsGameSession {
             ClientMessage::StartGame => {
                 if let Some(code) = &self.game_code {
                     match self.game_manager.start_game(code) {
-                        Ok(_) => ServerMessage::GameStarted,
+                        Ok(_) => {
+                            self.game_manager.broadcast(code, ServerMessage::GameStarted);
+                            return;
+                        }
                         Err(e) => ServerMessage::Error { message: e.to_string() }
                     }
                 } else {
@@ -100,13 +146,117 @@ impl WsGameSession {
             ClientMessage::SubmitSolution { used_hint } => {
                 if let Some(code) = &self.game_code {
                     match self.game_manager.submit_solution(code, &self.id, used_hint) {
-                        Ok(players) => ServerMessage::UpdateScores { players },
+                        Ok((players, finished)) => {
+                            self.game_manager
+                                .broadcast(code, ServerMessage::UpdateScores { players: players.clone() });
+                            if finished {
+                                self.game_manager.broadcast(code, ServerMessage::GameEnded { players });
+                            }
+                            return;
+                        }
                         Err(e) => ServerMessage::Error { message: e.to_string() }
                     }
                 } else {
                     ServerMessage::Error { message: "Not in a game".to_string() }
                 }
             }
+
+            ClientMessage::Resume { token } => {
+                match self.game_manager.resume(&token, ctx.address().recipient()) {
+                    Ok((code, player_id, game_state)) => {
+                        info!("Player {} resumed game {}", player_id, code);
+                        self.id = player_id;
+                        self.game_code = Some(code.clone());
+                        self.game_manager.broadcast(
+                            &code,
+                            ServerMessage::UpdateScores { players: game_state.get_players() },
+                        );
+                        return;
+                    }
+                    Err(e) => ServerMessage::Error { message: e.to_string() }
+                }
+            }
+
+            ClientMessage::EndGame => {
+                if let Some(code) = &self.game_code {
+                    match self.game_manager.end_game(code) {
+                        Ok(players) => {
+                            self.game_manager.broadcast(code, ServerMessage::GameEnded { players });
+                            return;
+                        }
+                        Err(e) => ServerMessage::Error { message: e.to_string() }
+                    }
+                } else {
+                    ServerMessage::Error { message: "Not in a game".to_string() }
+                }
+            }
+
+            ClientMessage::RequestRematch => {
+                if let Some(code) = &self.game_code {
+                    match self.game_manager.request_rematch(code, &self.id) {
+                        Ok(player) => {
+                            self.game_manager.broadcast(code, ServerMessage::RematchRequested { player });
+                            return;
+                        }
+                        Err(e) => ServerMessage::Error { message: e.to_string() }
+                    }
+                } else {
+                    ServerMessage::Error { message: "Not in a game".to_string() }
+                }
+            }
+
+            ClientMessage::AcceptRematch => {
+                if let Some(code) = &self.game_code {
+                    match self.game_manager.accept_rematch(code, &self.id) {
+                        Ok(Some(game_state)) => {
+                            self.game_manager.broadcast(code, ServerMessage::GameStarted);
+                            self.game_manager.broadcast(
+                                code,
+                                ServerMessage::UpdateScores { players: game_state.get_players() },
+                            );
+                            return;
+                        }
+                        Ok(None) => return,
+                        Err(e) => ServerMessage::Error { message: e.to_string() }
+                    }
+                } else {
+                    ServerMessage::Error { message: "Not in a game".to_string() }
+                }
+            }
+
+            ClientMessage::RejectRematch => {
+                if let Some(code) = &self.game_code {
+                    match self.game_manager.reject_rematch(code) {
+                        Ok(()) => {
+                            self.game_manager.broadcast(code, ServerMessage::RematchDeclined);
+                            return;
+                        }
+                        Err(e) => ServerMessage::Error { message: e.to_string() }
+                    }
+                } else {
+                    ServerMessage::Error { message: "Not in a game".to_string() }
+                }
+            }
+
+            ClientMessage::PlayRandom { player_name } => {
+                info!("Player {} looking for a random opponent", player_name);
+
+                match self.game_manager.play_random(self.id, player_name, ctx.address()) {
+                    Ok(crate::game::RandomMatchOutcome::Waiting) => ServerMessage::WaitingForOpponent,
+                    Ok(crate::game::RandomMatchOutcome::Matched { code, game_state }) => {
+                        self.game_code = Some(code.clone());
+                        self.game_manager
+                            .broadcast(&code, ServerMessage::OpponentConnected { game_code: code.clone() });
+                        self.game_manager.broadcast(&code, ServerMessage::GameStarted);
+                        self.game_manager.broadcast(
+                            &code,
+                            ServerMessage::UpdateScores { players: game_state.get_players() },
+                        );
+                        return;
+                    }
+                    Err(e) => ServerMessage::Error { message: e.to_string() }
+                }
+            }
         };
 
         if let Err(e) = serde_json::to_string(&response)
@@ -126,14 +276,34 @@ impl Actor for WsGameSession {
     }
 
     fn stopped(&mut self, _: &mut Self::Context) {
+        self.game_manager.cancel_play_random(&self.id);
         if let Some(code) = &self.game_code {
-            self.game_manager.remove_player(code, &self.id);
-            info!("Player {} removed from game {}", self.id, code);
+            self.game_manager.disconnect_player(code, &self.id);
+            info!("Player {} disconnected from game {}", self.id, code);
         }
         info!("Session stopped: {}", self.id);
     }
 }
 
+impl Handler<MatchFound> for WsGameSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: MatchFound, _ctx: &mut Self::Context) {
+        self.game_code = Some(msg.code);
+    }
+}
+
+impl Handler<OutgoingMessage> for WsGameSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: OutgoingMessage, ctx: &mut Self::Context) {
+        match serde_json::to_string(&msg.0) {
+            Ok(json) => ctx.text(json),
+            Err(e) => error!("Failed to serialize broadcast message: {}", e),
+        }
+    }
+}
+
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsGameSession {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         match msg {
@@ -0,0 +1,47 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::OnceLock;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Per-process signing key for resume tokens. Tokens are a deterministic
+/// function of (game code, player id) plus this secret, so the server
+/// doesn't need to persist anything to validate a resume attempt later.
+fn secret() -> &'static [u8; 16] {
+    static SECRET: OnceLock<[u8; 16]> = OnceLock::new();
+    SECRET.get_or_init(|| *Uuid::new_v4().as_bytes())
+}
+
+fn sign(code: &str, player_id: Uuid) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret()).expect("HMAC accepts any key length");
+    mac.update(code.as_bytes());
+    mac.update(player_id.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Issues an opaque resume token binding a player to a game code. The token
+/// is self-describing (it embeds the code and player id) and HMAC-signed,
+/// so `validate` can recover both without any server-side lookup table.
+pub fn issue(code: &str, player_id: Uuid) -> String {
+    format!("{code}.{player_id}.{}", sign(code, player_id))
+}
+
+/// Validates a resume token, returning the game code and player id it was
+/// issued for if the signature checks out.
+pub fn validate(token: &str) -> Option<(String, Uuid)> {
+    let mut parts = token.splitn(3, '.');
+    let code = parts.next()?;
+    let player_id = parts.next()?.parse::<Uuid>().ok()?;
+    let signature = parts.next()?;
+
+    if parts.next().is_some() {
+        return None;
+    }
+
+    if sign(code, player_id) == signature {
+        Some((code.to_string(), player_id))
+    } else {
+        None
+    }
+}